@@ -0,0 +1,57 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::{StatusCode, header},
+    middleware::Next,
+    response::Response,
+};
+use sha2::{Digest, Sha256};
+
+use crate::rate_limit;
+use crate::AppState;
+
+// The keeper ID bound to a request once its bearer token has been authenticated. Handlers pull
+// this out of request extensions and confirm it matches the `keeper_id` in the request body.
+#[derive(Debug, Clone)]
+pub struct AuthenticatedKeeper(pub String);
+
+// Authenticates the `Authorization: Bearer <token>` header against `keeper_tokens`, binding the
+// resulting keeper ID into the request's extensions, and enforces a rate limit keyed by that
+// keeper ID: all tokens issued to the same keeper share one budget.
+pub async fn require_auth(
+    State(state): State<Arc<AppState>>,
+    mut req: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+
+    let token_hash = hex::encode(Sha256::digest(token.as_bytes()));
+
+    let keeper_id = state
+        .db
+        .prepare("SELECT keeper_id FROM keeper_tokens WHERE token_hash = ? AND revoked = FALSE")
+        .bind(&[token_hash.into()])
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?
+        .first::<String>(Some("keeper_id"))
+        .await
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+
+    if !rate_limit::check_rate_limit(&state.db, &keeper_id)
+        .await
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?
+    {
+        return Err(StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    req.extensions_mut()
+        .insert(AuthenticatedKeeper(keeper_id));
+
+    Ok(next.run(req).await)
+}
@@ -0,0 +1,63 @@
+use serde::{Deserialize, Serialize};
+use worker::kv::KvStore;
+
+use crate::checksum::{BackupChecksum, ChecksumAlgorithm};
+
+// Refetching and recomputing the canonical checksum over the entire paginated artifact list on
+// every submitted backup is expensive, so we cache the result in KV for a short while and only
+// refetch once the cache is cold or has expired. We also cache the per-artifact leaf hashes
+// alongside the root: `backup_artifacts` needs those same leaf hashes for every ingested backup,
+// not just the ones that happen to trigger a live refetch, so callers must be able to get them
+// from the cache on a hit too.
+//
+// The TTL itself is deployment-configurable via the `CHECKSUM_CACHE_TTL_SECS` environment
+// variable; this is just the fallback used when that var is unset or unparseable.
+pub(crate) const DEFAULT_CACHE_TTL_SECS: u64 = 300;
+
+// Workers KV rejects `expiration_ttl` values below 60 seconds, so a misconfigured
+// `CHECKSUM_CACHE_TTL_SECS` below that would otherwise turn every cold-cache request into a 500.
+const KV_MIN_TTL_SECS: u64 = 60;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedChecksum {
+    digest: String,
+    artifact_leaf_hashes: Vec<(String, String)>,
+}
+
+fn cache_key(algorithm: ChecksumAlgorithm) -> String {
+    format!("canonical-checksum:{}", algorithm.as_str())
+}
+
+pub async fn get_cached_checksum(
+    kv: &KvStore,
+    algorithm: ChecksumAlgorithm,
+) -> worker::Result<Option<(BackupChecksum, Vec<(String, String)>)>> {
+    let cached = kv
+        .get(&cache_key(algorithm))
+        .json::<CachedChecksum>()
+        .await?;
+
+    Ok(cached.map(|cached| {
+        (
+            BackupChecksum::new(algorithm, cached.digest),
+            cached.artifact_leaf_hashes,
+        )
+    }))
+}
+
+pub async fn put_cached_checksum(
+    kv: &KvStore,
+    checksum: &BackupChecksum,
+    artifact_leaf_hashes: Vec<(String, String)>,
+    ttl_secs: u64,
+) -> worker::Result<()> {
+    let cached = CachedChecksum {
+        digest: checksum.digest().to_owned(),
+        artifact_leaf_hashes,
+    };
+
+    kv.put(&cache_key(checksum.algorithm()), cached)?
+        .expiration_ttl(ttl_secs.max(KV_MIN_TTL_SECS))
+        .execute()
+        .await
+}
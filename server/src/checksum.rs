@@ -1,3 +1,7 @@
+use std::fmt;
+use std::str::FromStr;
+
+use blake2::Blake2b512;
 use serde::Serialize;
 use sha2::{Digest, Sha256};
 use worker::wasm_bindgen::JsValue;
@@ -83,35 +87,511 @@ impl From<crate::api::ArtifactResponse> for ArtifactMetadata {
     }
 }
 
-fn compute_canonicalized_checksum<T: Serialize>(object: T) -> worker::Result<String> {
+// BLAKE2b supports variable-length output, but we only support its 64-byte (maximum) form: the
+// digest length isn't encoded in the wire format (`blake2b:<hex digest>`), so accepting multiple
+// lengths here would make that tag ambiguous between keepers. A keeper whose client produces a
+// shorter BLAKE2b digest (e.g. the common 32-byte BLAKE2b-256) isn't interoperable with this
+// server: the `blake2b` tag is only accepted with a 64-byte (BLAKE2b-512) digest.
+const BLAKE2B_DIGEST_BYTES: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumAlgorithm {
+    Sha256,
+    // BLAKE2b-512 specifically; see the note on `BLAKE2B_DIGEST_BYTES`.
+    Blake2b,
+    Blake3,
+}
+
+impl ChecksumAlgorithm {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Sha256 => "sha256",
+            Self::Blake2b => "blake2b",
+            Self::Blake3 => "blake3",
+        }
+    }
+
+    // The length, in bytes, of a digest produced by this algorithm.
+    fn digest_bytes(self) -> usize {
+        match self {
+            Self::Sha256 | Self::Blake3 => 32,
+            Self::Blake2b => BLAKE2B_DIGEST_BYTES,
+        }
+    }
+}
+
+impl FromStr for ChecksumAlgorithm {
+    type Err = ();
+
+    fn from_str(algorithm: &str) -> Result<Self, Self::Err> {
+        match algorithm {
+            "sha256" => Ok(Self::Sha256),
+            "blake2b" => Ok(Self::Blake2b),
+            "blake3" => Ok(Self::Blake3),
+            _ => Err(()),
+        }
+    }
+}
+
+// A backup checksum, tagged with the algorithm used to produce it. The wire format is
+// `<algorithm>:<hex digest>`, e.g. `sha256:9f86d0...`, which lets multiple keepers using
+// different hash algorithms coexist. Format version 1 backups predate this tagging and are
+// represented on the wire as a bare 64-character hex-encoded SHA-256 digest; see `parse` below.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BackupChecksum {
+    Sha256(String),
+    Blake2b(String),
+    Blake3(String),
+}
+
+impl BackupChecksum {
+    // Trusts that `digest` was produced by `algorithm`, unlike `parse`, which validates it; this
+    // is meant for digests we computed ourselves.
+    pub fn new(algorithm: ChecksumAlgorithm, digest: String) -> Self {
+        match algorithm {
+            ChecksumAlgorithm::Sha256 => Self::Sha256(digest),
+            ChecksumAlgorithm::Blake2b => Self::Blake2b(digest),
+            ChecksumAlgorithm::Blake3 => Self::Blake3(digest),
+        }
+    }
+
+    pub fn algorithm(&self) -> ChecksumAlgorithm {
+        match self {
+            Self::Sha256(_) => ChecksumAlgorithm::Sha256,
+            Self::Blake2b(_) => ChecksumAlgorithm::Blake2b,
+            Self::Blake3(_) => ChecksumAlgorithm::Blake3,
+        }
+    }
+
+    pub fn digest(&self) -> &str {
+        match self {
+            Self::Sha256(digest) | Self::Blake2b(digest) | Self::Blake3(digest) => digest,
+        }
+    }
+
+    // Parses a checksum in `<algorithm>:<hex digest>` form. For `format_version` 1, a bare
+    // hex-encoded SHA-256 digest (with no algorithm prefix) is also accepted, since that format
+    // version predates algorithm tagging.
+    pub fn parse(checksum: &str, format_version: u32) -> Option<Self> {
+        match checksum.split_once(':') {
+            Some((algorithm, digest)) => {
+                let algorithm: ChecksumAlgorithm = algorithm.to_ascii_lowercase().parse().ok()?;
+
+                is_hex_digest(digest, algorithm.digest_bytes()).then(|| {
+                    let digest = digest.to_owned();
+
+                    match algorithm {
+                        ChecksumAlgorithm::Sha256 => Self::Sha256(digest),
+                        ChecksumAlgorithm::Blake2b => Self::Blake2b(digest),
+                        ChecksumAlgorithm::Blake3 => Self::Blake3(digest),
+                    }
+                })
+            }
+            None if format_version == 1 => {
+                is_hex_digest(checksum, ChecksumAlgorithm::Sha256.digest_bytes())
+                    .then(|| Self::Sha256(checksum.to_owned()))
+            }
+            None => None,
+        }
+    }
+}
+
+impl fmt::Display for BackupChecksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm().as_str(), self.digest())
+    }
+}
+
+fn is_hex_digest(digest: &str, expected_bytes: usize) -> bool {
+    digest.len() == expected_bytes * 2 && digest.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn canonicalize<T: Serialize>(object: T) -> worker::Result<Vec<u8>> {
     serde_json_canonicalizer::to_vec(&object)
         .map_err(|e| worker::Error::from(JsValue::from_str(&format!("Serialization error: {}", e))))
-        .map(|canonicalized_json| {
-            let digest = Sha256::digest(&canonicalized_json);
-            hex::encode(digest)
-        })
+}
+
+fn hash_bytes(algorithm: ChecksumAlgorithm, bytes: &[u8]) -> Vec<u8> {
+    match algorithm {
+        ChecksumAlgorithm::Sha256 => Sha256::digest(bytes).to_vec(),
+        ChecksumAlgorithm::Blake2b => Blake2b512::digest(bytes).to_vec(),
+        ChecksumAlgorithm::Blake3 => blake3::hash(bytes).as_bytes().to_vec(),
+    }
+}
+
+fn artifact_leaf_hash_bytes(
+    artifact: &ArtifactResponse,
+    algorithm: ChecksumAlgorithm,
+) -> worker::Result<Vec<u8>> {
+    let metadata = ArtifactMetadata::from(artifact.clone());
+    let canonicalized_json = canonicalize(&metadata)?;
+
+    Ok(hash_bytes(algorithm, &canonicalized_json))
+}
+
+// Computes a per-artifact leaf hash under the given algorithm: the digest of the
+// JCS-canonicalized metadata for a single artifact. This is the same value used as a Merkle leaf
+// in `compute_backup_checksum`, so a `backup_artifacts` row recorded under a given algorithm
+// reconciles with an inclusion proof computed for that same algorithm. This also lets a keeper
+// negotiate an incremental backup plan (see `POST /backups/plan`) by comparing leaf hashes
+// instead of reprocessing the whole backup.
+pub fn compute_leaf_hash(
+    artifact: &ArtifactResponse,
+    algorithm: ChecksumAlgorithm,
+) -> worker::Result<String> {
+    Ok(hex::encode(artifact_leaf_hash_bytes(artifact, algorithm)?))
 }
 
 // We need to compute a checksum of the backup which is deterministic, stable, and agnostic to the
-// on-disk backup format.
-//
-// To accomplish this, we assemble a list of all the artifact metadata in the backup, canonicalize
-// it via RFC 8785 (JSON Canonicalization Scheme), and hash the canonicalized JSON representation.
+// on-disk backup format, and which lets a verifier prove that a single artifact belongs to the
+// backup without reprocessing every other artifact.
 //
-// The JCS format ensures that two semantically identical JSON objects will always serialize to the
-// same byte sequence, regardless of field ordering, number formatting, whitespace, etc. We sort
-// the array of artifact metadata objects lexicographically by artifact ID.
+// To accomplish this, we build a Merkle tree over the backup's artifacts: each leaf is the digest
+// of a single artifact's metadata, canonicalized via RFC 8785 (JSON Canonicalization Scheme) so
+// that the digest doesn't depend on field ordering, number formatting, whitespace, etc. Leaves are
+// sorted lexicographically by artifact ID so the tree is deterministic regardless of the order
+// artifacts are returned upstream. Each internal node is the digest of its two children
+// concatenated; when a level has an odd number of nodes, the last one is duplicated so it can be
+// paired with itself. The root of the tree is the backup checksum.
 //
 // Because the artifact metadata already includes a hash of each file, we only need to hash the
 // metadata.
-pub fn compute_backup_checksum(api_response: &[ArtifactResponse]) -> worker::Result<String> {
-    let mut sorted_metadata = api_response
+fn merkle_leaves(
+    api_response: &[ArtifactResponse],
+    algorithm: ChecksumAlgorithm,
+) -> worker::Result<Vec<(String, Vec<u8>)>> {
+    let mut sorted_artifacts = api_response.to_vec();
+    sorted_artifacts.sort_by_key(|artifact| artifact.id.clone());
+
+    sorted_artifacts
+        .iter()
+        .map(|artifact| Ok((artifact.id.clone(), artifact_leaf_hash_bytes(artifact, algorithm)?)))
+        .collect()
+}
+
+// Combines a level of the Merkle tree into its parent level, duplicating the last node if the
+// level has an odd number of nodes.
+fn merkle_parent_level(algorithm: ChecksumAlgorithm, mut level: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+    if level.len() % 2 == 1 {
+        level.push(level.last().expect("level is non-empty").clone());
+    }
+
+    level
+        .chunks(2)
+        .map(|pair| {
+            let mut combined = pair[0].clone();
+            combined.extend_from_slice(&pair[1]);
+            hash_bytes(algorithm, &combined)
+        })
+        .collect()
+}
+
+// Folds a level of leaf hashes up to the root. Pulled out of `compute_backup_checksum` so it can
+// be pinned against fixed trees in tests without going through `ArtifactResponse`/JCS.
+fn merkle_root_from_leaves(algorithm: ChecksumAlgorithm, leaves: Vec<Vec<u8>>) -> Vec<u8> {
+    if leaves.is_empty() {
+        // An empty backup has no leaves to hash; define its root as the hash of an empty input.
+        return hash_bytes(algorithm, &[]);
+    }
+
+    let mut level = leaves;
+
+    while level.len() > 1 {
+        level = merkle_parent_level(algorithm, level);
+    }
+
+    level.into_iter().next().expect("level has one element")
+}
+
+pub fn compute_backup_checksum(
+    api_response: &[ArtifactResponse],
+    algorithm: ChecksumAlgorithm,
+) -> worker::Result<BackupChecksum> {
+    let level: Vec<Vec<u8>> = merkle_leaves(api_response, algorithm)?
+        .into_iter()
+        .map(|(_id, hash)| hash)
+        .collect();
+
+    Ok(BackupChecksum::new(
+        algorithm,
+        hex::encode(merkle_root_from_leaves(algorithm, level)),
+    ))
+}
+
+// Which side of its parent a Merkle proof step's sibling hash sits on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MerkleSide {
+    Left,
+    Right,
+}
+
+// One step of a Merkle inclusion proof: the hash of the sibling node, and which side of the
+// parent it belongs on when reconstructing the path to the root.
+#[derive(Debug, Clone, Serialize)]
+pub struct MerkleProofStep {
+    pub sibling_hash: String,
+    pub side: MerkleSide,
+}
+
+// Computes an inclusion proof for a single leaf, given its index in the (already leaf-ordered)
+// tree: the sibling hashes along the path from that leaf to the root, in bottom-up order. Pulled
+// out of `compute_inclusion_proof` so it can be pinned against fixed trees in tests.
+fn merkle_proof_from_leaves(
+    algorithm: ChecksumAlgorithm,
+    leaves: Vec<Vec<u8>>,
+    mut index: usize,
+) -> Vec<MerkleProofStep> {
+    let mut level = leaves;
+    let mut proof = Vec::new();
+
+    while level.len() > 1 {
+        if level.len() % 2 == 1 {
+            level.push(level.last().expect("level is non-empty").clone());
+        }
+
+        let (sibling_index, side) = if index % 2 == 0 {
+            (index + 1, MerkleSide::Right)
+        } else {
+            (index - 1, MerkleSide::Left)
+        };
+
+        proof.push(MerkleProofStep {
+            sibling_hash: hex::encode(&level[sibling_index]),
+            side,
+        });
+
+        level = merkle_parent_level(algorithm, level);
+        index /= 2;
+    }
+
+    proof
+}
+
+// Computes an inclusion proof for a single artifact against the leaves recorded for a specific
+// backup (i.e. `backup_artifacts` rows), not a live upstream fetch: a proof only means something
+// when it's built from the same tree that produced the backup's recorded root, and upstream
+// content can change at any time after a backup is ingested. `stored_leaves` is the
+// `(artifact_id, leaf_hash)` pairs recorded for that backup; the caller is responsible for
+// fetching them for the right `backup_id` and for using the algorithm that backup was recorded
+// under.
+//
+// Returns `None` if no artifact with the given ID is present among `stored_leaves`.
+pub fn compute_inclusion_proof(
+    stored_leaves: &[(String, String)],
+    algorithm: ChecksumAlgorithm,
+    artifact_id: &str,
+) -> worker::Result<Option<Vec<MerkleProofStep>>> {
+    let mut sorted_leaves = stored_leaves.to_vec();
+    sorted_leaves.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let Some(index) = sorted_leaves
         .iter()
-        .cloned()
-        .map(ArtifactMetadata::from)
-        .collect::<Vec<_>>();
+        .position(|(id, _leaf_hash)| id == artifact_id)
+    else {
+        return Ok(None);
+    };
+
+    let level = sorted_leaves
+        .into_iter()
+        .map(|(_id, leaf_hash)| {
+            hex::decode(leaf_hash).map_err(|e| {
+                worker::Error::from(JsValue::from_str(&format!("Invalid stored leaf hash: {e}")))
+            })
+        })
+        .collect::<worker::Result<Vec<_>>>()?;
+
+    Ok(Some(merkle_proof_from_leaves(algorithm, level, index)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Verifies a proof by folding it into `leaf_hash` and checking it reproduces `root`.
+    fn verify_proof(leaf_hash: &[u8], proof: &[MerkleProofStep], root: &[u8]) -> bool {
+        let folded = proof.iter().fold(leaf_hash.to_vec(), |acc, step| {
+            let sibling = hex::decode(&step.sibling_hash).expect("valid hex");
+
+            let mut combined = match step.side {
+                MerkleSide::Left => sibling.clone(),
+                MerkleSide::Right => acc.clone(),
+            };
+
+            combined.extend_from_slice(match step.side {
+                MerkleSide::Left => &acc,
+                MerkleSide::Right => &sibling,
+            });
+
+            hash_bytes(ChecksumAlgorithm::Sha256, &combined)
+        });
+
+        folded == root
+    }
+
+    fn synthetic_leaves(count: usize) -> Vec<Vec<u8>> {
+        (0..count)
+            .map(|i| hash_bytes(ChecksumAlgorithm::Sha256, format!("leaf-{i}").as_bytes()))
+            .collect()
+    }
+
+    #[test]
+    fn single_leaf_root_is_the_leaf_itself() {
+        let leaves = synthetic_leaves(1);
+        let root = merkle_root_from_leaves(ChecksumAlgorithm::Sha256, leaves.clone());
+
+        assert_eq!(root, leaves[0]);
+        assert_eq!(
+            hex::encode(&root),
+            "d2dbf006f96dd05044a8f63d8f118f23925ba4cc5750f8b6c8e287fd506c8188"
+        );
+
+        let proof = merkle_proof_from_leaves(ChecksumAlgorithm::Sha256, leaves.clone(), 0);
+        assert!(proof.is_empty());
+        assert!(verify_proof(&leaves[0], &proof, &root));
+    }
+
+    #[test]
+    fn two_leaves_pair_directly() {
+        let leaves = synthetic_leaves(2);
+        let root = merkle_root_from_leaves(ChecksumAlgorithm::Sha256, leaves.clone());
 
-    sorted_metadata.sort_by_key(|artifact| artifact.id.clone());
+        assert_eq!(
+            hex::encode(&root),
+            "8b0f563106070048a1057926820c7118dec20b8a73715544f4528487c16dc0d7"
+        );
 
-    compute_canonicalized_checksum(&sorted_metadata)
+        for index in 0..leaves.len() {
+            let proof =
+                merkle_proof_from_leaves(ChecksumAlgorithm::Sha256, leaves.clone(), index);
+            assert_eq!(proof.len(), 1);
+            assert!(verify_proof(&leaves[index], &proof, &root));
+        }
+    }
+
+    #[test]
+    fn three_leaves_duplicate_the_last_odd_node() {
+        let leaves = synthetic_leaves(3);
+        let root = merkle_root_from_leaves(ChecksumAlgorithm::Sha256, leaves.clone());
+
+        assert_eq!(
+            hex::encode(&root),
+            "39313694557e76d28b720ad7f4481cb144c24c8341f8a68fc4a8363fcd1a04bb"
+        );
+
+        for index in 0..leaves.len() {
+            let proof =
+                merkle_proof_from_leaves(ChecksumAlgorithm::Sha256, leaves.clone(), index);
+            assert!(verify_proof(&leaves[index], &proof, &root));
+        }
+    }
+
+    #[test]
+    fn five_leaves_proofs_all_verify_against_the_root() {
+        let leaves = synthetic_leaves(5);
+        let root = merkle_root_from_leaves(ChecksumAlgorithm::Sha256, leaves.clone());
+
+        assert_eq!(
+            hex::encode(&root),
+            "3ad4abec5d43ae09f5275cf7ce77d8615e1e87164b255aa7661e237b1982a5bf"
+        );
+
+        for index in 0..leaves.len() {
+            let proof =
+                merkle_proof_from_leaves(ChecksumAlgorithm::Sha256, leaves.clone(), index);
+            assert!(verify_proof(&leaves[index], &proof, &root));
+        }
+    }
+
+    #[test]
+    fn empty_backup_root_is_hash_of_empty_input() {
+        let root = merkle_root_from_leaves(ChecksumAlgorithm::Sha256, Vec::new());
+        assert_eq!(root, hash_bytes(ChecksumAlgorithm::Sha256, &[]));
+    }
+
+    #[test]
+    fn parses_tagged_checksums_per_algorithm() {
+        let sha256_digest = "a".repeat(64);
+        let blake2b_digest = "b".repeat(128);
+        let blake3_digest = "c".repeat(64);
+
+        assert_eq!(
+            BackupChecksum::parse(&format!("sha256:{sha256_digest}"), 2),
+            Some(BackupChecksum::Sha256(sha256_digest.clone()))
+        );
+        assert_eq!(
+            BackupChecksum::parse(&format!("blake2b:{blake2b_digest}"), 2),
+            Some(BackupChecksum::Blake2b(blake2b_digest.clone()))
+        );
+        assert_eq!(
+            BackupChecksum::parse(&format!("blake3:{blake3_digest}"), 2),
+            Some(BackupChecksum::Blake3(blake3_digest))
+        );
+    }
+
+    #[test]
+    fn algorithm_tag_is_case_insensitive() {
+        let digest = "a".repeat(64);
+
+        assert_eq!(
+            BackupChecksum::parse(&format!("SHA256:{digest}"), 2),
+            Some(BackupChecksum::Sha256(digest))
+        );
+    }
+
+    #[test]
+    fn rejects_digest_with_wrong_length_for_its_algorithm() {
+        // A 32-byte digest tagged as blake2b (which expects 64 bytes) doesn't parse.
+        let short_digest = "a".repeat(64);
+        assert_eq!(
+            BackupChecksum::parse(&format!("blake2b:{short_digest}"), 2),
+            None
+        );
+
+        // Conversely, a 64-byte digest tagged as sha256 (which expects 32 bytes) doesn't parse.
+        let long_digest = "a".repeat(128);
+        assert_eq!(
+            BackupChecksum::parse(&format!("sha256:{long_digest}"), 2),
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_algorithm_tag() {
+        let digest = "a".repeat(64);
+        assert_eq!(BackupChecksum::parse(&format!("md5:{digest}"), 2), None);
+    }
+
+    #[test]
+    fn format_version_1_accepts_bare_sha256_digest_with_no_tag() {
+        let digest = "a".repeat(64);
+
+        assert_eq!(
+            BackupChecksum::parse(&digest, 1),
+            Some(BackupChecksum::Sha256(digest.clone()))
+        );
+
+        // But an untagged digest is rejected for later format versions, which require tagging.
+        assert_eq!(BackupChecksum::parse(&digest, 2), None);
+    }
+
+    #[test]
+    fn rejects_non_hex_digest() {
+        let not_hex = "z".repeat(64);
+        assert_eq!(BackupChecksum::parse(&format!("sha256:{not_hex}"), 2), None);
+    }
+
+    #[test]
+    fn is_valid_checksum_matches_parse_success() {
+        let digest = "a".repeat(64);
+
+        assert!(crate::validate::is_valid_checksum(
+            &format!("sha256:{digest}"),
+            2
+        ));
+        assert!(!crate::validate::is_valid_checksum(
+            &format!("sha256:{}", "a".repeat(10)),
+            2
+        ));
+    }
 }
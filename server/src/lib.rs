@@ -1,24 +1,32 @@
 mod api;
+mod auth;
+mod cache;
 mod checksum;
+mod rate_limit;
 mod validate;
 
-use std::{fmt, sync::Arc};
+use std::{collections::HashMap, fmt, sync::Arc};
 
 use axum::{
     Router,
-    extract::{Json, State},
+    extract::{Extension, Json, State},
     http::StatusCode,
+    middleware,
     response::NoContent,
     routing::post,
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tower_service::Service;
 use worker::{wasm_bindgen::JsValue, *};
 
+use crate::auth::AuthenticatedKeeper;
+use crate::checksum::{BackupChecksum, ChecksumAlgorithm, MerkleProofStep};
 use crate::validate::{is_valid_checksum, is_valid_format_version, is_valid_keeper_id};
 
 struct AppState {
     pub db: D1Database,
+    pub kv: kv::KvStore,
+    pub checksum_cache_ttl_secs: u64,
 }
 
 impl fmt::Debug for AppState {
@@ -28,9 +36,17 @@ impl fmt::Debug for AppState {
 }
 
 fn router(state: AppState) -> Router {
+    let state = Arc::new(state);
+
     Router::new()
         .route("/backups", post(post_backup))
-        .with_state(Arc::new(state))
+        .route("/backups/plan", post(plan_backup))
+        .route("/backups/proof", post(backup_inclusion_proof))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::require_auth,
+        ))
+        .with_state(state)
 }
 
 #[event(fetch)]
@@ -39,10 +55,44 @@ async fn fetch(
     env: Env,
     _ctx: Context,
 ) -> Result<axum::http::Response<axum::body::Body>> {
-    let state = AppState { db: env.d1("DB")? };
+    let checksum_cache_ttl_secs = env
+        .var("CHECKSUM_CACHE_TTL_SECS")
+        .ok()
+        .and_then(|var| var.to_string().parse().ok())
+        .unwrap_or(cache::DEFAULT_CACHE_TTL_SECS);
+
+    let state = AppState {
+        db: env.d1("DB")?,
+        kv: env.kv("CHECKSUM_CACHE")?,
+        checksum_cache_ttl_secs,
+    };
     Ok(router(state).call(req).await?)
 }
 
+// Shared by every handler that takes a `keeper_id` in its request body: confirms it's a valid UUID
+// and matches the keeper the bearer token was issued to, so a keeper can't act on another keeper's
+// behalf just by naming their ID in the body.
+fn authorize_keeper_id(
+    body_keeper_id: &str,
+    authenticated_keeper_id: &str,
+) -> std::result::Result<(), StatusCode> {
+    if !is_valid_keeper_id(body_keeper_id) {
+        console_error!("Keeper ID is not a valid UUID: {}", body_keeper_id);
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    if body_keeper_id != authenticated_keeper_id {
+        console_error!(
+            "Keeper ID {} does not match the token owner {}",
+            body_keeper_id,
+            authenticated_keeper_id
+        );
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    Ok(())
+}
+
 #[derive(Debug, Deserialize)]
 struct BackupRequest {
     format_version: u32,
@@ -56,6 +106,7 @@ struct BackupRequest {
 #[worker::send]
 async fn post_backup(
     State(state): State<Arc<AppState>>,
+    Extension(AuthenticatedKeeper(authenticated_keeper_id)): Extension<AuthenticatedKeeper>,
     Json(body): Json<BackupRequest>,
 ) -> std::result::Result<NoContent, StatusCode> {
     if !is_valid_format_version(body.format_version) {
@@ -63,12 +114,9 @@ async fn post_backup(
         return Err(StatusCode::BAD_REQUEST);
     }
 
-    if !is_valid_keeper_id(&body.keeper_id) {
-        console_error!("Keeper ID is not a valid UUID: {}", &body.keeper_id);
-        return Err(StatusCode::BAD_REQUEST);
-    }
+    authorize_keeper_id(&body.keeper_id, &authenticated_keeper_id)?;
 
-    if !is_valid_checksum(&body.checksum) {
+    if !is_valid_checksum(&body.checksum, body.format_version) {
         console_error!(
             "Checksum had an unexpected length or encoding: {}",
             &body.checksum
@@ -76,18 +124,77 @@ async fn post_backup(
         return Err(StatusCode::BAD_REQUEST);
     }
 
-    state
+    // Already validated above, so the checksum is guaranteed to parse.
+    let checksum = BackupChecksum::parse(&body.checksum.to_ascii_lowercase(), body.format_version)
+        .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    // The per-artifact leaf hashes for this backup's algorithm, used below to populate
+    // `backup_artifacts` so keepers can negotiate incremental plans against it later. These come
+    // from the checksum cache regardless of whether this request is what populated it, so a
+    // `backup_artifacts` row is recorded for every backup, not just the ones that happen to
+    // refetch upstream.
+    let (canonical_checksum, artifact_leaf_hashes) =
+        match cache::get_cached_checksum(&state.kv, checksum.algorithm())
+            .await
+            .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?
+        {
+            Some(cached) => cached,
+            None => {
+                let artifacts = api::fetch_all_artifacts()
+                    .await
+                    .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+
+                let computed = checksum::compute_backup_checksum(&artifacts, checksum.algorithm())
+                    .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+
+                let leaf_hashes = artifacts
+                    .iter()
+                    .map(|artifact| {
+                        Ok((
+                            artifact.id.clone(),
+                            checksum::compute_leaf_hash(artifact, checksum.algorithm())?,
+                        ))
+                    })
+                    .collect::<worker::Result<Vec<_>>>()
+                    .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+
+                cache::put_cached_checksum(
+                    &state.kv,
+                    &computed,
+                    leaf_hashes.clone(),
+                    state.checksum_cache_ttl_secs,
+                )
+                .await
+                .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+
+                (computed, leaf_hashes)
+            }
+        };
+
+    if !checksum
+        .digest()
+        .eq_ignore_ascii_case(canonical_checksum.digest())
+    {
+        console_error!(
+            "Submitted checksum does not match canonical upstream checksum: {}",
+            &body.checksum
+        );
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    let insert_result = state
         .db
         .prepare(
             r#"
-            INSERT INTO backups (format_version, keeper_id, checksum, size, contact, contact_type)
-            VALUES (?, ?, ?, ?, ?, ?)
+            INSERT INTO backups (format_version, keeper_id, checksum, checksum_algorithm, size, contact, contact_type)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
             "#,
         )
         .bind(&[
             body.format_version.into(),
             body.keeper_id.clone().into(),
-            body.checksum.to_ascii_lowercase().into(),
+            checksum.digest().into(),
+            checksum.algorithm().as_str().into(),
             // An f64 can only losslessly represent integers up to 2^53. In context, that's 8 PiB
             // (pebibytes). We don't need to worry about it.
             (body.size as f64).into(),
@@ -105,6 +212,31 @@ async fn post_backup(
         .await
         .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
 
+    let backup_id = insert_result
+        .meta()
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?
+        .and_then(|meta| meta.last_row_id)
+        .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let leaf_hash_statements = artifact_leaf_hashes
+        .into_iter()
+        .map(|(artifact_id, leaf_hash)| {
+            state
+                .db
+                .prepare(
+                    "INSERT INTO backup_artifacts (backup_id, artifact_id, leaf_hash) VALUES (?, ?, ?)",
+                )
+                .bind(&[backup_id.into(), artifact_id.into(), leaf_hash.into()])
+                .or(Err(StatusCode::INTERNAL_SERVER_ERROR))
+        })
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+
+    state
+        .db
+        .batch(leaf_hash_statements)
+        .await
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+
     console_log!(
         "Inserted backup record.\nKeeper ID: {}\nChecksum: {}\nSize: {}\nContact: {}\nFormat Version: {}",
         body.keeper_id,
@@ -116,3 +248,271 @@ async fn post_backup(
 
     Ok(NoContent)
 }
+
+#[derive(Debug, Deserialize)]
+struct KnownArtifact {
+    artifact_id: String,
+    leaf_hash: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct PlanRequest {
+    keeper_id: String,
+    algorithm: String,
+    known_artifacts: Vec<KnownArtifact>,
+}
+
+#[derive(Debug, Serialize)]
+struct PlanResponse {
+    added: Vec<String>,
+    changed: Vec<String>,
+    removed: Vec<String>,
+}
+
+#[axum::debug_handler]
+#[worker::send]
+async fn plan_backup(
+    Extension(AuthenticatedKeeper(authenticated_keeper_id)): Extension<AuthenticatedKeeper>,
+    Json(body): Json<PlanRequest>,
+) -> std::result::Result<Json<PlanResponse>, StatusCode> {
+    authorize_keeper_id(&body.keeper_id, &authenticated_keeper_id)?;
+
+    let algorithm: ChecksumAlgorithm = body
+        .algorithm
+        .to_ascii_lowercase()
+        .parse()
+        .or(Err(StatusCode::BAD_REQUEST))?;
+
+    let artifacts = api::fetch_all_artifacts()
+        .await
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    let mut upstream_leaf_hashes: HashMap<String, String> = HashMap::with_capacity(artifacts.len());
+
+    for artifact in &artifacts {
+        let leaf_hash = checksum::compute_leaf_hash(artifact, algorithm)
+            .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+
+        upstream_leaf_hashes.insert(artifact.id.clone(), leaf_hash);
+    }
+
+    let known_leaf_hashes: HashMap<String, String> = body
+        .known_artifacts
+        .into_iter()
+        .map(|known| (known.artifact_id, known.leaf_hash))
+        .collect();
+
+    Ok(Json(diff_known_artifacts(
+        &upstream_leaf_hashes,
+        &known_leaf_hashes,
+    )))
+}
+
+// Compares a keeper's previously known artifact leaf hashes against the current upstream set,
+// sorted for a deterministic response. Pulled out of `plan_backup` so the set-difference logic can
+// be tested without a live upstream fetch.
+fn diff_known_artifacts(
+    upstream_leaf_hashes: &HashMap<String, String>,
+    known_leaf_hashes: &HashMap<String, String>,
+) -> PlanResponse {
+    let mut added = Vec::new();
+    let mut changed = Vec::new();
+
+    for (artifact_id, leaf_hash) in upstream_leaf_hashes {
+        match known_leaf_hashes.get(artifact_id) {
+            None => added.push(artifact_id.clone()),
+            Some(known_leaf_hash) if known_leaf_hash != leaf_hash => {
+                changed.push(artifact_id.clone())
+            }
+            Some(_) => {}
+        }
+    }
+
+    let mut removed: Vec<String> = known_leaf_hashes
+        .keys()
+        .filter(|artifact_id| !upstream_leaf_hashes.contains_key(*artifact_id))
+        .cloned()
+        .collect();
+
+    added.sort();
+    changed.sort();
+    removed.sort();
+
+    PlanResponse {
+        added,
+        changed,
+        removed,
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct InclusionProofRequest {
+    keeper_id: String,
+    backup_id: i64,
+    artifact_id: String,
+}
+
+#[derive(Debug, Serialize)]
+struct InclusionProofResponse {
+    leaf_hash: String,
+    proof: Vec<MerkleProofStep>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StoredLeaf {
+    artifact_id: String,
+    leaf_hash: String,
+}
+
+// Lets a keeper verify that a single artifact was part of a specific, previously recorded backup
+// without reprocessing every other artifact. The proof is built from that backup's own
+// `backup_artifacts` rows (written at ingest time in `post_backup`), not a live upstream fetch:
+// upstream content can change at any time after a backup is recorded, so a proof built against
+// "whatever upstream looks like now" would fold to a different root than the one the keeper
+// actually submitted.
+#[axum::debug_handler]
+#[worker::send]
+async fn backup_inclusion_proof(
+    State(state): State<Arc<AppState>>,
+    Extension(AuthenticatedKeeper(authenticated_keeper_id)): Extension<AuthenticatedKeeper>,
+    Json(body): Json<InclusionProofRequest>,
+) -> std::result::Result<Json<InclusionProofResponse>, StatusCode> {
+    authorize_keeper_id(&body.keeper_id, &authenticated_keeper_id)?;
+
+    let checksum_algorithm = state
+        .db
+        .prepare("SELECT checksum_algorithm FROM backups WHERE id = ? AND keeper_id = ?")
+        .bind(&[body.backup_id.into(), body.keeper_id.clone().into()])
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?
+        .first::<String>(Some("checksum_algorithm"))
+        .await
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let algorithm: ChecksumAlgorithm = checksum_algorithm
+        .parse()
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+
+    let stored_leaves: Vec<(String, String)> = state
+        .db
+        .prepare("SELECT artifact_id, leaf_hash FROM backup_artifacts WHERE backup_id = ?")
+        .bind(&[body.backup_id.into()])
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?
+        .all()
+        .await
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?
+        .results::<StoredLeaf>()
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?
+        .into_iter()
+        .map(|leaf| (leaf.artifact_id, leaf.leaf_hash))
+        .collect();
+
+    let leaf_hash = stored_leaves
+        .iter()
+        .find(|(artifact_id, _leaf_hash)| *artifact_id == body.artifact_id)
+        .map(|(_artifact_id, leaf_hash)| leaf_hash.clone())
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let proof = checksum::compute_inclusion_proof(&stored_leaves, algorithm, &body.artifact_id)
+        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok(Json(InclusionProofResponse { leaf_hash, proof }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn authorize_keeper_id_rejects_non_uuid() {
+        assert_eq!(
+            authorize_keeper_id("not-a-uuid", "not-a-uuid"),
+            Err(StatusCode::BAD_REQUEST)
+        );
+    }
+
+    #[test]
+    fn authorize_keeper_id_rejects_mismatched_keeper() {
+        let body_keeper_id = "3fa85f64-5717-4562-b3fc-2c963f66afa6";
+        let authenticated_keeper_id = "3fa85f64-5717-4562-b3fc-2c963f66afa7";
+
+        assert_eq!(
+            authorize_keeper_id(body_keeper_id, authenticated_keeper_id),
+            Err(StatusCode::FORBIDDEN)
+        );
+    }
+
+    #[test]
+    fn authorize_keeper_id_accepts_matching_keeper() {
+        let keeper_id = "3fa85f64-5717-4562-b3fc-2c963f66afa6";
+        assert_eq!(authorize_keeper_id(keeper_id, keeper_id), Ok(()));
+    }
+
+    fn hashes(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(id, hash)| (id.to_string(), hash.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn new_upstream_artifact_is_added() {
+        let upstream = hashes(&[("a", "hash-a")]);
+        let known = hashes(&[]);
+
+        let plan = diff_known_artifacts(&upstream, &known);
+
+        assert_eq!(plan.added, vec!["a"]);
+        assert!(plan.changed.is_empty());
+        assert!(plan.removed.is_empty());
+    }
+
+    #[test]
+    fn changed_leaf_hash_is_changed_not_added_or_removed() {
+        let upstream = hashes(&[("a", "hash-a-new")]);
+        let known = hashes(&[("a", "hash-a-old")]);
+
+        let plan = diff_known_artifacts(&upstream, &known);
+
+        assert!(plan.added.is_empty());
+        assert_eq!(plan.changed, vec!["a"]);
+        assert!(plan.removed.is_empty());
+    }
+
+    #[test]
+    fn unchanged_leaf_hash_is_neither_added_changed_nor_removed() {
+        let upstream = hashes(&[("a", "hash-a")]);
+        let known = hashes(&[("a", "hash-a")]);
+
+        let plan = diff_known_artifacts(&upstream, &known);
+
+        assert!(plan.added.is_empty());
+        assert!(plan.changed.is_empty());
+        assert!(plan.removed.is_empty());
+    }
+
+    #[test]
+    fn known_artifact_missing_upstream_is_removed() {
+        let upstream = hashes(&[]);
+        let known = hashes(&[("a", "hash-a")]);
+
+        let plan = diff_known_artifacts(&upstream, &known);
+
+        assert!(plan.added.is_empty());
+        assert!(plan.changed.is_empty());
+        assert_eq!(plan.removed, vec!["a"]);
+    }
+
+    #[test]
+    fn results_are_sorted_and_mixed_cases_are_independent() {
+        let upstream = hashes(&[("b", "hash-b"), ("c", "hash-c-new"), ("a", "hash-a")]);
+        let known = hashes(&[("c", "hash-c-old"), ("d", "hash-d"), ("a", "hash-a")]);
+
+        let plan = diff_known_artifacts(&upstream, &known);
+
+        assert_eq!(plan.added, vec!["b"]);
+        assert_eq!(plan.changed, vec!["c"]);
+        assert_eq!(plan.removed, vec!["d"]);
+    }
+}
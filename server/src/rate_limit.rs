@@ -0,0 +1,93 @@
+use rand::Rng;
+use worker::{D1Database, Date};
+
+// A true sliding window: each request is its own row in `token_requests`, and a keeper is within
+// budget if fewer than `MAX_REQUESTS_PER_WINDOW` of its rows fall within the last `WINDOW_SECS`.
+// The insert-if-under-budget check has to be atomic or two concurrent requests could both read the
+// count before either's insert lands, letting both through once the budget is already exhausted.
+// D1 runs a single statement atomically, so we fold the count check and the insert into one
+// `INSERT ... SELECT ... WHERE` statement instead of doing a separate read then write.
+const WINDOW_SECS: i64 = 60;
+const MAX_REQUESTS_PER_WINDOW: u64 = 30;
+
+// Stale rows are already excluded from the count by the `requested_at > window_start` filter, so
+// deleting them isn't needed for correctness, only to keep the table from growing unbounded.
+// Running it on every request would double the D1 round-trips on the hot auth path, so we only do
+// it for a small fraction of requests.
+const CLEANUP_PROBABILITY: f64 = 0.01;
+
+// The start of the trailing window as of `now_secs`, exclusive: a row with `requested_at ==
+// window_start` has already aged out (matches the `requested_at > window_start` comparison used
+// both to count a keeper's requests and, conversely, to select stale rows for cleanup).
+fn window_start(now_secs: i64) -> i64 {
+    now_secs - WINDOW_SECS
+}
+
+// Returns `true` if the keeper identified by `keeper_id` is still within its request budget for
+// the trailing window, recording this request as a side effect. Returns `false` once the budget
+// has been exhausted, without recording the request.
+pub async fn check_rate_limit(db: &D1Database, keeper_id: &str) -> worker::Result<bool> {
+    let now_secs = Date::now().as_millis() as i64 / 1000;
+    let window_start = window_start(now_secs);
+
+    let result = db
+        .prepare(
+            r#"
+            INSERT INTO token_requests (keeper_id, requested_at)
+            SELECT ?, ?
+            WHERE (
+                SELECT COUNT(*) FROM token_requests
+                WHERE keeper_id = ? AND requested_at > ?
+            ) < ?
+            "#,
+        )
+        .bind(&[
+            keeper_id.into(),
+            now_secs.into(),
+            keeper_id.into(),
+            window_start.into(),
+            MAX_REQUESTS_PER_WINDOW.into(),
+        ])?
+        .run()
+        .await?;
+
+    let inserted = result.meta()?.and_then(|meta| meta.changes).unwrap_or(0) > 0;
+
+    if inserted && rand::rng().random_bool(CLEANUP_PROBABILITY) {
+        // Best-effort cleanup of rows that have already aged out of every future window; losing
+        // this to a transient error doesn't affect correctness, only table size.
+        let _ = db
+            .prepare("DELETE FROM token_requests WHERE keeper_id = ? AND requested_at <= ?")
+            .bind(&[keeper_id.into(), window_start.into()])?
+            .run()
+            .await;
+    }
+
+    Ok(inserted)
+}
+
+// `check_rate_limit` itself needs a live D1 binding and isn't exercised here; these tests pin the
+// window-boundary arithmetic it relies on (a request exactly `WINDOW_SECS` old has just aged out,
+// matching the `requested_at > window_start` comparison used in the query above).
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_start_is_window_secs_before_now() {
+        let now_secs = 1_700_000_000;
+        assert_eq!(window_start(now_secs), now_secs - WINDOW_SECS);
+    }
+
+    #[test]
+    fn request_exactly_at_window_start_has_aged_out() {
+        let now_secs = 1_700_000_000;
+        let start = window_start(now_secs);
+
+        // The query counts `requested_at > window_start`, so a row timestamped exactly at the
+        // boundary is excluded...
+        assert!(!(start > start));
+        // ...while a row one second newer is still within the window.
+        assert!(start + 1 > start);
+    }
+}
@@ -1,7 +1,8 @@
 use uuid::Uuid;
 
-const CURRENT_FORMAT_VERSION: u32 = 1;
-const CHECKSUM_BYTES: usize = 32;
+use crate::checksum::BackupChecksum;
+
+const CURRENT_FORMAT_VERSION: u32 = 2;
 
 pub fn is_valid_format_version(format_version: u32) -> bool {
     format_version > 0 && format_version <= CURRENT_FORMAT_VERSION
@@ -14,10 +15,6 @@ pub fn is_valid_keeper_id(keeper_id: &str) -> bool {
     }
 }
 
-pub fn is_valid_checksum(checksum: &str) -> bool {
-    if checksum.len() != CHECKSUM_BYTES * 2 {
-        return false;
-    }
-
-    checksum.chars().all(|c| c.is_ascii_hexdigit())
+pub fn is_valid_checksum(checksum: &str, format_version: u32) -> bool {
+    BackupChecksum::parse(checksum, format_version).is_some()
 }